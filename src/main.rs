@@ -1,48 +1,100 @@
-use std::{
-    ops::{Add, Div},
-    time::Duration,
-};
+use std::time::Duration;
 
+use avian2d::prelude::*;
 use bevy::{
     asset::RenderAssetUsages,
+    diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
     prelude::*,
     render::mesh::{Indices, PrimitiveTopology},
 };
 use bevy_egui::{EguiContexts, EguiPlugin};
 use bevy_spatial::{kdtree::KDTree3, AutomaticUpdate, SpatialAccess};
 use egui::Slider;
+use opensimplex_noise_rs::OpenSimplexNoise;
 use rand::{distributions::Uniform, Rng};
 
 fn main() {
     let mut app = App::new();
     app.add_plugins(DefaultPlugins)
         .add_plugins(EguiPlugin)
-        .add_plugins(AutomaticUpdate::<Boid>::new().with_frequency(Duration::from_millis(300)))
+        .add_plugins(FrameTimeDiagnosticsPlugin)
+        .add_plugins(AutomaticUpdate::<Boid>::new().with_frequency(Duration::from_millis(300)));
+
+    if USE_AVIAN_PHYSICS {
+        app.add_plugins(PhysicsPlugins::default());
+    }
+
+    app
         .insert_resource(BoidArgs {
             cohesion: 1.0,
             alignment: 1.0,
             seperation: 1.0,
+            avoidance: 1.0,
             range: 100.0,
         })
+        .insert_resource(BenchmarkMode {
+            enabled: false,
+            target: 10_000,
+        })
+        .insert_resource(resolve_sim_mode())
+        .insert_resource(VolumeBounds {
+            half_extents: Vec3::splat(300.0),
+        })
+        .insert_resource(AttractorArgs { strength: 20_000.0 })
         .add_systems(Update, draw_ui)
         .add_systems(Startup, setup)
         .add_systems(Update, update_pos)
         .add_event::<UpdateVelocity>()
         .add_systems(Update, update_velocity)
+        .add_systems(Update, sync_avian_velocity.before(update_prox_cache))
+        .add_systems(Update, limit_avian_speed)
+        .add_systems(Update, update_prox_cache.before(boid_rules))
         .add_systems(Update, boid_rules)
         .add_systems(Update, avoid_edges)
+        .add_systems(Update, apply_attractors)
+        .add_systems(Update, spawn_boids_on_click)
+        .add_systems(Update, place_attractor_on_click)
+        .add_systems(Update, benchmark_spawn)
         .run();
 }
 
 const WIDTH: f32 = 5.0;
 const HEIGHT: f32 = 10.0;
 
+/// Swaps the hand-rolled integrator in `update_pos`/`update_velocity` for an Avian2D
+/// rigid-body simulation: boids get a `RigidBody`/`Collider`/`LinearVelocity` and the
+/// flocking rules push `ExternalForce` instead of mutating `Velocity` directly, which
+/// also gets boid-boid and boid-obstacle collision response for free. `SimMode::Flat`
+/// only — Avian2D doesn't apply to the 3D volumetric mode.
+const USE_AVIAN_PHYSICS: bool = false;
+
+/// Default `SimMode` `setup` spawns into when the `SIM_MODE` env var isn't set (or
+/// isn't recognized); see `resolve_sim_mode`. See `SimMode` for why this is a startup
+/// flag rather than something toggled live from `draw_ui`.
+const SIM_MODE: SimMode = SimMode::Flat;
+
+/// Reads the `SIM_MODE` env var (`"flat"` or `"volumetric"`, case-insensitive) to pick
+/// the startup `SimMode`, falling back to `SIM_MODE` the const when unset/unrecognized.
+fn resolve_sim_mode() -> SimMode {
+    match std::env::var("SIM_MODE") {
+        Ok(value) if value.eq_ignore_ascii_case("volumetric") => SimMode::Volumetric,
+        Ok(value) if value.eq_ignore_ascii_case("flat") => SimMode::Flat,
+        _ => SIM_MODE,
+    }
+}
+
 #[derive(Component)]
-#[require(Velocity)]
+#[require(Velocity, ProxCache)]
 struct Boid;
 
 type SpatialTree = KDTree3<Boid>;
 
+/// Neighbor entity, position and velocity within `BoidArgs::range`, refreshed once per
+/// frame by [`update_prox_cache`] so `boid_rules` can fold cohesion/alignment/separation
+/// in a single pass instead of querying the spatial tree three times.
+#[derive(Component, Clone, Default, Debug)]
+struct ProxCache(Vec<(Entity, Vec3, Vec3)>);
+
 struct BoidMeshBuilder;
 impl MeshBuilder for BoidMeshBuilder {
     fn build(&self) -> Mesh {
@@ -85,178 +137,696 @@ struct BoidArgs {
     cohesion: f32,
     alignment: f32,
     seperation: f32,
+    avoidance: f32,
     range: f32,
 }
 
+/// A circular obstacle the flock steers around, placed during `setup`.
+#[derive(Component, Clone, Copy, Debug)]
+struct Obstacle {
+    pos: Vec3,
+    radius: f32,
+}
+
+/// A point that pulls boids in with inverse-square "gravity" (positive `strength`) or
+/// pushes them away like a predator (negative `strength`). Placed by right-click.
+#[derive(Component, Clone, Copy, Debug)]
+struct Attractor {
+    pos: Vec3,
+    strength: f32,
+}
+
+/// Global strength newly-placed attractors are given; tunable from `draw_ui`.
+#[derive(Debug, Resource)]
+struct AttractorArgs {
+    strength: f32,
+}
+
+/// Builds a jittered-ring obstacle mesh: `base_radius` perturbed by OpenSimplex noise
+/// sampled around the perimeter so obstacles read as rocks rather than perfect circles.
+struct ObstacleMeshBuilder {
+    base_radius: f32,
+    seed: i64,
+}
+
+impl MeshBuilder for ObstacleMeshBuilder {
+    fn build(&self) -> Mesh {
+        const SEGMENTS: usize = 32;
+
+        let simplex = OpenSimplexNoise::new(Some(self.seed));
+        let mut positions = vec![[0.0, 0.0, 0.0]];
+        for i in 0..=SEGMENTS {
+            let angle_factor = i as f64 / SEGMENTS as f64;
+            let angle = angle_factor as f32 * std::f32::consts::TAU;
+            // Sample noise off the angle's (cos, sin) rather than the raw angle_factor,
+            // so angle_factor == 0.0 and == 1.0 (the same point on the ring) land on the
+            // same noise sample and the closing triangle meets the opening one exactly.
+            let (cos, sin) = (angle.cos() as f64, angle.sin() as f64);
+            let noise = simplex.eval_2d(cos * 3.0, sin * 3.0)
+                + 0.5 * simplex.eval_2d(cos * 7.0 + 10.0, sin * 7.0 + 10.0);
+            let radius = self.base_radius + noise as f32 * self.base_radius * 0.3;
+            positions.push([angle.cos() * radius, angle.sin() * radius, 0.0]);
+        }
+
+        let mut indices = Vec::with_capacity(SEGMENTS * 3);
+        for i in 1..=SEGMENTS as u32 {
+            indices.extend_from_slice(&[0, i, i + 1]);
+        }
+
+        let normals = vec![[0.0, 0.0, 1.0]; positions.len()];
+        let uvs = vec![[0.5, 0.5]; positions.len()];
+
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices))
+    }
+}
+
+/// Spawns `count` obstacles at random positions within the window, resampling each up to
+/// `MAX_PLACEMENT_ATTEMPTS` times to keep it clear of those already placed; when no clear
+/// spot turns up, the last sample is used and may overlap another obstacle.
+fn spawn_obstacles(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    window: &Window,
+    count: usize,
+) {
+    let mut rng = rand::thread_rng();
+    let xrange = Uniform::new(-window.width() / 2.0 + 60.0, window.width() / 2.0 - 60.0);
+    let yrange = Uniform::new(-window.height() / 2.0 + 60.0, window.height() / 2.0 - 60.0);
+    let radius_range = Uniform::new(30.0, 60.0);
+
+    // Caps the non-overlap search per obstacle so a tight window/high count can't spin
+    // forever resampling an already-full free space; past this many tries we just place
+    // it anyway and accept the overlap.
+    const MAX_PLACEMENT_ATTEMPTS: u32 = 100;
+
+    let mut placed: Vec<(Vec3, f32)> = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut candidate = (Vec3::ZERO, 0.0);
+        for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+            let pos = Vec3::new(rng.sample(xrange), rng.sample(yrange), 0.0);
+            let radius = rng.sample(radius_range);
+            candidate = (pos, radius);
+            if placed
+                .iter()
+                .all(|(p, r)| pos.distance(*p) > radius + r + 20.0)
+            {
+                break;
+            }
+        }
+        let (pos, radius) = candidate;
+
+        let mut entity = commands.spawn((
+            Obstacle { pos, radius },
+            Mesh2d(meshes.add(ObstacleMeshBuilder {
+                base_radius: radius,
+                seed: i as i64,
+            })),
+            MeshMaterial2d(materials.add(ColorMaterial::from_color(Color::srgba(
+                0.4, 0.4, 0.4, 1.0,
+            )))),
+            Transform::from_translation(pos),
+        ));
+        if USE_AVIAN_PHYSICS {
+            entity.insert((RigidBody::Static, Collider::circle(radius)));
+        }
+        placed.push((pos, radius));
+    }
+}
+
+/// Whether the flock flies on a flat plane (2D camera, window-rectangle bounds) or
+/// inside a 3D volume (3D camera, box bounds). Set once before `Startup`; the render
+/// components a boid needs differ between modes, so this isn't hot-swappable at runtime.
+#[derive(Resource, Clone, Copy, PartialEq, Eq)]
+enum SimMode {
+    Flat,
+    Volumetric,
+}
+
+/// Half-extents of the axis-aligned box boids are kept inside while in
+/// `SimMode::Volumetric`. Unused in `SimMode::Flat`, which bounds against the window instead.
+#[derive(Resource, Clone, Copy)]
+struct VolumeBounds {
+    half_extents: Vec3,
+}
+
+/// Shared mesh/material handles for spawning boids outside of `setup`, e.g. from the
+/// mouse-spawn system or the egui "spawn" button. Holds the 2D or 3D render handles
+/// depending on the active [`SimMode`].
+#[derive(Resource, Clone)]
+enum BoidAssets {
+    Flat {
+        mesh: Handle<Mesh>,
+        material: Handle<ColorMaterial>,
+    },
+    Volumetric {
+        mesh: Handle<Mesh>,
+        material: Handle<StandardMaterial>,
+    },
+}
+
+/// How many boids the egui "spawn" button adds at a time.
+#[derive(Resource)]
+struct BoidCount(pub usize);
+
+/// Drives the flock up to `target` boids a batch at a time so the flocking systems can
+/// be stress-tested at scale without manually mashing the spawn button.
+#[derive(Resource)]
+struct BenchmarkMode {
+    enabled: bool,
+    target: usize,
+}
+
+fn spawn_boid(commands: &mut Commands, assets: &BoidAssets, pos: Vec3, velocity: Vec3) {
+    let transform = Transform::from_translation(pos);
+    let linear_velocity = velocity.truncate();
+    let velocity = Velocity(velocity);
+    match assets {
+        BoidAssets::Flat { mesh, material } => {
+            let mut entity = commands.spawn((
+                Boid,
+                Mesh2d(mesh.clone()),
+                MeshMaterial2d(material.clone()),
+                transform,
+                velocity,
+            ));
+            if USE_AVIAN_PHYSICS {
+                entity.insert((
+                    RigidBody::Dynamic,
+                    Collider::circle(HEIGHT / 2.0),
+                    LinearVelocity(linear_velocity),
+                    // Non-persistent: each frame's steering deltas should replace, not
+                    // accumulate onto, the previous frame's force.
+                    ExternalForce::default().with_persistence(false),
+                ));
+            }
+        }
+        BoidAssets::Volumetric { mesh, material } => {
+            commands.spawn((
+                Boid,
+                Mesh3d(mesh.clone()),
+                MeshMaterial3d(material.clone()),
+                transform,
+                velocity,
+            ));
+        }
+    }
+}
+
+fn spawn_random_boids(
+    commands: &mut Commands,
+    assets: &BoidAssets,
+    bounds: &VolumeBounds,
+    window: &Window,
+    count: usize,
+) {
+    let mut rng = rand::thread_rng();
+    match assets {
+        BoidAssets::Flat { .. } => {
+            let xrange = Uniform::new(-window.width() / 2.0, window.width() / 2.0);
+            let yrange = Uniform::new(-window.height() / 2.0, window.height() / 2.0);
+            for _ in 0..count {
+                let pos = Vec3::new(rng.sample(xrange), rng.sample(yrange), 0.0);
+                spawn_boid(commands, assets, pos, Vec3::new(10.0, 10.0, 0.0));
+            }
+        }
+        BoidAssets::Volumetric { .. } => {
+            let xrange = Uniform::new(-bounds.half_extents.x, bounds.half_extents.x);
+            let yrange = Uniform::new(-bounds.half_extents.y, bounds.half_extents.y);
+            let zrange = Uniform::new(-bounds.half_extents.z, bounds.half_extents.z);
+            for _ in 0..count {
+                let pos = Vec3::new(rng.sample(xrange), rng.sample(yrange), rng.sample(zrange));
+                spawn_boid(commands, assets, pos, Vec3::new(10.0, 10.0, 10.0));
+            }
+        }
+    }
+}
+
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    mut std_materials: ResMut<Assets<StandardMaterial>>,
+    mode: Res<SimMode>,
+    bounds: Res<VolumeBounds>,
+    window: Query<&Window>,
+) {
+    let assets = match *mode {
+        SimMode::Flat => {
+            commands.spawn(Camera2d);
+            spawn_obstacles(&mut commands, &mut meshes, &mut materials, window.single(), 5);
+            BoidAssets::Flat {
+                mesh: meshes.add(BoidMeshBuilder),
+                material: materials
+                    .add(ColorMaterial::from_color(Color::srgba(1.0, 0.0, 1.0, 1.0))),
+            }
+        }
+        SimMode::Volumetric => {
+            commands.spawn((
+                Camera3d::default(),
+                Transform::from_xyz(0.0, 0.0, bounds.half_extents.z * 3.0)
+                    .looking_at(Vec3::ZERO, Vec3::Y),
+            ));
+            commands.spawn((
+                DirectionalLight::default(),
+                Transform::default().looking_at(Vec3::new(-1.0, -1.0, -1.0), Vec3::Y),
+            ));
+            BoidAssets::Volumetric {
+                mesh: meshes.add(Cone::new(WIDTH / 2.0, HEIGHT)),
+                material: std_materials
+                    .add(StandardMaterial::from_color(Color::srgba(1.0, 0.0, 1.0, 1.0))),
+            }
+        }
+    };
+
+    spawn_random_boids(&mut commands, &assets, &bounds, window.single(), 100);
+
+    commands.insert_resource(assets);
+    commands.insert_resource(BoidCount(50));
+
+    info!("Starting!");
+}
+
+/// Spawns a boid at the cursor's world position while the left mouse button is held,
+/// reusing the shared mesh/material from [`BoidAssets`]. One boid per frame gives a
+/// paint-brush feel rather than a single click-to-spawn. Only supported in
+/// `SimMode::Flat`, which has a 2D camera to unproject the cursor through.
+fn spawn_boids_on_click(
+    mouse: Res<ButtonInput<MouseButton>>,
+    mode: Res<SimMode>,
+    window: Query<&Window>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    assets: Res<BoidAssets>,
+    mut commands: Commands,
+) {
+    if *mode != SimMode::Flat || !mouse.pressed(MouseButton::Left) {
+        return;
+    }
+
+    let window = window.single();
+    let Some(cursor) = window.cursor_position() else {
+        return;
+    };
+    let (camera, camera_transform) = camera.single();
+    let Ok(world_pos) = camera.viewport_to_world_2d(camera_transform, cursor) else {
+        return;
+    };
+
+    spawn_boid(
+        &mut commands,
+        &assets,
+        world_pos.extend(0.0),
+        Vec3::new(10.0, 10.0, 0.0),
+    );
+}
+
+/// Places an `Attractor` at the cursor's world position on right-click, using the
+/// global strength tuned in `draw_ui`. Flat-mode only, same as `spawn_boids_on_click`.
+fn place_attractor_on_click(
+    mouse: Res<ButtonInput<MouseButton>>,
+    mode: Res<SimMode>,
+    attractor_args: Res<AttractorArgs>,
     window: Query<&Window>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    mut commands: Commands,
 ) {
-    commands.spawn(Camera2d);
-    let circle = meshes.add(BoidMeshBuilder);
-    let color = materials.add(ColorMaterial::from_color(Color::srgba(1.0, 0.0, 1.0, 1.0)));
+    if *mode != SimMode::Flat || !mouse.just_pressed(MouseButton::Right) {
+        return;
+    }
 
     let window = window.single();
-    let xrange = Uniform::new(-window.width() / 2.0, window.width() / 2.0);
-    let yrange = Uniform::new(-window.height() / 2.0, window.height() / 2.0);
-    let mut rng = rand::thread_rng();
-    for _ in 0..100 {
-        commands.spawn((
-            Boid,
-            Mesh2d(circle.clone()),
-            MeshMaterial2d(color.clone()),
-            Transform::from_xyz(rng.sample(xrange), rng.sample(yrange), 0.0),
-            Velocity(Vec3::new(10.0, 10.0, 0.0)),
-        ));
+    let Some(cursor) = window.cursor_position() else {
+        return;
+    };
+    let (camera, camera_transform) = camera.single();
+    let Ok(world_pos) = camera.viewport_to_world_2d(camera_transform, cursor) else {
+        return;
+    };
+
+    commands.spawn(Attractor {
+        pos: world_pos.extend(0.0),
+        strength: attractor_args.strength,
+    });
+}
+
+/// Injects an inverse-square steering delta from every `Attractor` within `CUTOFF`,
+/// clamped near the point so the force doesn't blow up.
+fn apply_attractors(
+    time: Res<Time>,
+    birds: Query<(&Transform, Entity), With<Boid>>,
+    attractors: Query<&Attractor>,
+    mut update_vel: EventWriter<UpdateVelocity>,
+) {
+    const CUTOFF: f32 = 600.0;
+    const MIN_DIST_SQUARED: f32 = 2500.0;
+
+    for (transform, entity) in &birds {
+        let my_pos = transform.translation;
+        let delta = attractors.iter().fold(Vec3::ZERO, |acc, attractor| {
+            let offset = attractor.pos - my_pos;
+            let Some(dir) = offset.try_normalize() else {
+                return acc;
+            };
+            if offset.length() > CUTOFF {
+                return acc;
+            }
+            acc + dir * attractor.strength / offset.length_squared().max(MIN_DIST_SQUARED)
+        });
+
+        update_vel.send(UpdateVelocity(entity, delta * time.delta_secs()));
     }
+}
 
-    info!("Starting!");
+/// Tops the flock up towards `BenchmarkMode::target` in batches, so the window stays
+/// responsive while the population ramps up to stress-test scale.
+fn benchmark_spawn(
+    benchmark: Res<BenchmarkMode>,
+    bounds: Res<VolumeBounds>,
+    birds: Query<Entity, With<Boid>>,
+    assets: Res<BoidAssets>,
+    window: Query<&Window>,
+    mut commands: Commands,
+) {
+    if !benchmark.enabled {
+        return;
+    }
+
+    const BATCH: usize = 200;
+    let current = birds.iter().count();
+    let to_spawn = BATCH.min(benchmark.target.saturating_sub(current));
+    if to_spawn > 0 {
+        spawn_random_boids(&mut commands, &assets, &bounds, window.single(), to_spawn);
+    }
 }
 
-fn update_pos(time: Res<Time>, mut objects: Query<(&Velocity, &mut Transform)>) {
+/// Manual Euler integration for boids not under Avian's control. Avian-driven boids
+/// (with a `RigidBody`) get their `Transform` updated by the physics step instead.
+fn update_pos(
+    time: Res<Time>,
+    mut objects: Query<(&Velocity, &mut Transform), Without<RigidBody>>,
+) {
     for (velocity, mut transform) in &mut objects {
         transform.translation += velocity.0 * 0.5 * time.delta_secs();
     }
 }
+
 fn update_velocity(
     time: Res<Time>,
     mut ev: EventReader<UpdateVelocity>,
-    mut birds: Query<(&mut Velocity, &mut Transform)>,
+    mut birds: Query<(&mut Velocity, &mut Transform, Option<&mut ExternalForce>)>,
 ) {
     for UpdateVelocity(entity, vel) in ev.read() {
-        let Ok(mut bird) = birds.get_mut(*entity) else {
+        let Ok((mut velocity, mut transform, external_force)) = birds.get_mut(*entity) else {
             return;
         };
-        bird.0 .0 += vel;
+
+        // Under Avian, steering deltas are forces on the rigid body, not direct
+        // velocity mutations; `sync_avian_velocity`/`limit_avian_speed` take it from here.
+        if let Some(mut force) = external_force {
+            force.apply_force(vel.truncate());
+            continue;
+        }
+
+        velocity.0 += vel;
 
         // Add some friction
-        let friction = bird.0.0 * 0.1;
-        bird.0.0 -= friction * time.delta_secs();
+        let friction = velocity.0 * 0.1;
+        velocity.0 -= friction * time.delta_secs();
 
         const MAX_VELOCITY: f32 = 500.0;
         const MIN_VELOCITY: f32 = 50.0;
-        if bird.0 .0.length() > MAX_VELOCITY {
-            bird.0 .0 = bird.0 .0.normalize() * MAX_VELOCITY;
+        if velocity.0.length() > MAX_VELOCITY {
+            velocity.0 = velocity.0.normalize() * MAX_VELOCITY;
         }
-        if bird.0 .0.length() < MIN_VELOCITY {
-            bird.0 .0 = bird.0 .0.normalize() * MIN_VELOCITY;
+        if velocity.0.length() < MIN_VELOCITY {
+            velocity.0 = velocity.0.normalize() * MIN_VELOCITY;
         }
 
-        if let Some(norm) = bird.0 .0.try_normalize() {
-            bird.1.rotation = Quat::from_rotation_arc(Vec3::Y, norm)
+        if let Some(norm) = velocity.0.try_normalize() {
+            transform.rotation = Quat::from_rotation_arc(Vec3::Y, norm)
         }
     }
 }
 
-/// Updates velocity by some delta
-#[derive(Event)]
-struct UpdateVelocity(pub Entity, pub Vec3);
+/// Mirrors Avian's `LinearVelocity` back into `Velocity`/`Transform` so the flocking
+/// rules (which read `Velocity` for heading and `ProxCache` for neighbor speed) see the
+/// same motion the physics engine is actually producing. A no-op when no boid has a
+/// `LinearVelocity`, i.e. when `USE_AVIAN_PHYSICS` is off.
+fn sync_avian_velocity(mut birds: Query<(&LinearVelocity, &mut Velocity, &mut Transform)>) {
+    for (linear, mut velocity, mut transform) in &mut birds {
+        velocity.0 = linear.0.extend(0.0);
+        if let Some(norm) = velocity.0.try_normalize() {
+            transform.rotation = Quat::from_rotation_arc(Vec3::Y, norm);
+        }
+    }
+}
+
+/// The min/max speed clamp for Avian-driven boids, mirroring the friction-integrator's
+/// clamp but operating on `LinearVelocity` instead of the hand-rolled `Velocity`.
+fn limit_avian_speed(mut birds: Query<&mut LinearVelocity, With<Boid>>) {
+    const MAX_VELOCITY: f32 = 500.0;
+    const MIN_VELOCITY: f32 = 50.0;
 
-/// Calculates the average of an iterator of vectors or anything divisible by f32
-fn average<T>(first: T, it: impl Iterator<Item = T>) -> T
-where
-    T: Add<T, Output = T>,
-    T: Div<f32, Output = T>,
-{
-    let (sum, len) = it.fold((first, 0), |(a, count), e| (a + e, count + 1));
-    if len == 0 {
-        sum
-    } else {
-        sum / len as f32
+    for mut velocity in &mut birds {
+        let speed = velocity.0.length();
+        if speed > MAX_VELOCITY {
+            velocity.0 = velocity.0.normalize() * MAX_VELOCITY;
+        } else if speed > 0.0 && speed < MIN_VELOCITY {
+            velocity.0 = velocity.0.normalize() * MIN_VELOCITY;
+        }
     }
 }
 
+/// Updates velocity by some delta
+#[derive(Event)]
+struct UpdateVelocity(pub Entity, pub Vec3);
+
 const BORDER: f32 = 10.0;
 fn avoid_edges(
     time: Res<Time>,
+    mode: Res<SimMode>,
+    bounds: Res<VolumeBounds>,
     window: Query<&Window>,
     birds: Query<(&Transform, Entity)>,
     mut update_vel: EventWriter<UpdateVelocity>,
 ) {
-    let window = window.single();
+    match *mode {
+        SimMode::Flat => {
+            let window = window.single();
+            for (transform, entity) in &birds {
+                // Avoid edges by rotating toward center
+                let Vec3 { x, y, .. } = transform.translation;
+                let distance_to_edge =
+                    (window.width() / 2.0 - x.abs()).min(window.height() / 2.0 - y.abs());
 
-    for (transform, entity) in &birds {
-        // Avoid edges by rotating toward center
-        let Vec3 { x, y, .. } = transform.translation;
-        let distance_to_edge =
-            (window.width() / 2.0 - x.abs()).min(window.height() / 2.0 - y.abs());
+                let avoid_delta = if distance_to_edge < BORDER {
+                    (Vec3::ZERO - transform.translation) / (distance_to_edge.max(0.01) / 40.0)
+                } else {
+                    Vec3::ZERO
+                };
+                update_vel.send(UpdateVelocity(entity, avoid_delta * time.delta_secs()));
+            }
+        }
+        SimMode::Volumetric => {
+            for (transform, entity) in &birds {
+                // Avoid the nearest of the six box faces by rotating toward the center
+                let pos = transform.translation;
+                let distance_to_face = (bounds.half_extents - pos.abs()).min_element();
 
-        let avoid_delta = if distance_to_edge < BORDER {
-            (Vec3::ZERO - transform.translation) / (distance_to_edge.max(0.01) / 40.0)
-        } else {
-            Vec3::ZERO
-        };
-        update_vel.send(UpdateVelocity(entity, avoid_delta * time.delta_secs()));
+                let avoid_delta = if distance_to_face < BORDER {
+                    (Vec3::ZERO - pos) / (distance_to_face.max(0.01) / 40.0)
+                } else {
+                    Vec3::ZERO
+                };
+                update_vel.send(UpdateVelocity(entity, avoid_delta * time.delta_secs()));
+            }
+        }
+    }
+}
+
+/// Populates each boid's [`ProxCache`] with the neighbors reported by the spatial tree,
+/// resolving their `Velocity` once so `boid_rules` doesn't have to re-query for it.
+fn update_prox_cache(
+    boidargs: Res<BoidArgs>,
+    tree: Res<SpatialTree>,
+    birds: Query<(Entity, &Transform, &Velocity)>,
+    mut caches: Query<&mut ProxCache>,
+) {
+    let range = boidargs.range;
+
+    for (entity, transform, _) in &birds {
+        let my_pos = transform.translation;
+        let neighbors = tree
+            .within_distance(my_pos, range)
+            .iter()
+            .filter_map(|(p, e)| {
+                let e = (*e)?;
+                if e == entity {
+                    return None;
+                }
+                let (_, _, velocity) = birds.get(e).ok()?;
+                Some((e, *p, velocity.0))
+            })
+            .collect();
+
+        if let Ok(mut cache) = caches.get_mut(entity) {
+            cache.0 = neighbors;
+        }
     }
 }
 
 fn boid_rules(
     time: Res<Time>,
     boidargs: Res<BoidArgs>,
-    birds: Query<(&Velocity, &Transform, Entity)>,
-    tree: Res<SpatialTree>,
+    birds: Query<(&Velocity, &Transform, &ProxCache, Entity)>,
+    obstacles: Query<&Obstacle>,
     mut update_vel: EventWriter<UpdateVelocity>,
 ) {
     let BoidArgs {
         cohesion,
         alignment,
         seperation,
+        avoidance,
         range,
     } = *boidargs;
 
-    for (velocity, my_transform, my_entity) in &birds {
+    for (velocity, my_transform, cache, my_entity) in &birds {
         let Some(my_dir) = velocity.0.try_normalize() else {
             continue;
         };
         let my_pos = my_transform.translation;
         const VIEW_ANGLE: f32 = std::f32::consts::PI / 3.0;
 
-        // Fly towards center
-        let target = average(
-            Vec3::ZERO,
-            tree.within_distance(my_pos, range)
-                .iter()
-                .map(|(p, _)| *p)
-                .filter(|p| (p - my_pos).angle_between(my_dir) < VIEW_ANGLE),
-        );
-        let cohesion_delta = target - my_pos;
+        // Single pass over the cached neighbors: fold the cohesion target, alignment
+        // velocity and separation push all at once instead of walking the tree 3 times.
+        let (cohesion_sum, align_sum, seperation_sum, count) = cache.0.iter().fold(
+            (Vec3::ZERO, Vec3::ZERO, Vec3::ZERO, 0u32),
+            |(cohesion_sum, align_sum, seperation_sum, count), (_, pos, vel)| {
+                let offset = *pos - my_pos;
+                if offset.angle_between(my_dir) >= VIEW_ANGLE {
+                    return (cohesion_sum, align_sum, seperation_sum, count);
+                }
 
-        // Align with others
-        let align_delta = average(
-            Vec3::ZERO,
-            tree.within_distance(my_pos, range)
-                .iter()
-                .filter_map(|(p, e)| {
-                    (((p - my_pos).angle_between(my_dir) < VIEW_ANGLE) && *e != Some(my_entity))
-                        .then(|| Some(birds.get((*e)?).ok()?.0))
-                        .flatten()
-                        .map(|v| v.0)
-                }),
+                let away = my_pos - *pos;
+                (
+                    cohesion_sum + *pos,
+                    align_sum + *vel,
+                    seperation_sum + away / (away.length().max(0.001) / range),
+                    count + 1,
+                )
+            },
         );
 
-        // Avoid others
-        let seperation_delta = average(
-            Vec3::ZERO,
-            tree.within_distance(my_pos, range)
-                .iter()
-                .map(|(p, _)| my_pos - p)
-                .filter(|p| (p - my_pos).angle_between(my_dir) < VIEW_ANGLE)
-                .map(|v| v / (v.length().max(0.001) / range))
-        );
-        let del =
-            cohesion * cohesion_delta + alignment * align_delta + seperation * seperation_delta;
+        // With no neighbors in view, this matches the pre-cache behavior: averaging an
+        // empty set of positions gave a cohesion target of Vec3::ZERO, i.e. a pull
+        // toward the world origin. Alignment/separation still have nothing to average.
+        let (cohesion_delta, align_delta, seperation_delta) = if count == 0 {
+            (-my_pos, Vec3::ZERO, Vec3::ZERO)
+        } else {
+            (
+                cohesion_sum / count as f32 - my_pos,
+                align_sum / count as f32,
+                seperation_sum / count as f32,
+            )
+        };
+
+        // Steer away from nearby obstacles, pushing harder the closer the edge is. A
+        // boid at or inside the obstacle radius still needs the strongest possible
+        // push out, so clamp clearance to a small positive epsilon instead of zeroing
+        // the term out.
+        const MIN_CLEARANCE: f32 = 0.1;
+        let avoidance_delta = obstacles.iter().fold(Vec3::ZERO, |acc, obstacle| {
+            let offset = my_pos - obstacle.pos;
+            let clearance = offset.length() - obstacle.radius;
+            if clearance > range {
+                return acc;
+            }
+            let Some(dir) = offset.try_normalize() else {
+                return acc;
+            };
+            acc + dir / clearance.max(MIN_CLEARANCE)
+        });
+
+        let del = cohesion * cohesion_delta
+            + alignment * align_delta
+            + seperation * seperation_delta
+            + avoidance * avoidance_delta;
 
         update_vel.send(UpdateVelocity(my_entity, del * time.delta_secs()));
     }
 }
 
-fn draw_ui(mut boidargs: ResMut<BoidArgs>, mut contexts: EguiContexts) {
+// Bevy systems take one param per resource/query by design; grouping these into fewer
+// resources is worth doing if this grows further, but isn't warranted yet.
+#[allow(clippy::too_many_arguments)]
+fn draw_ui(
+    mut boidargs: ResMut<BoidArgs>,
+    mut boid_count: ResMut<BoidCount>,
+    mut benchmark: ResMut<BenchmarkMode>,
+    mut attractor_args: ResMut<AttractorArgs>,
+    bounds: Res<VolumeBounds>,
+    diagnostics: Res<DiagnosticsStore>,
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    assets: Res<BoidAssets>,
+    birds: Query<Entity, With<Boid>>,
+    window: Query<&Window>,
+) {
+    let current_count = birds.iter().count();
+    let fps = diagnostics
+        .get(&FrameTimeDiagnosticsPlugin::FPS)
+        .and_then(|d| d.smoothed())
+        .unwrap_or(0.0);
+    let frame_time = diagnostics
+        .get(&FrameTimeDiagnosticsPlugin::FRAME_TIME)
+        .and_then(|d| d.smoothed())
+        .unwrap_or(0.0);
+
     egui::Window::new("Boids").show(contexts.ctx_mut(), |ui| {
         ui.add(Slider::new(&mut boidargs.cohesion, 0.0..=2.0).text("Cohesion"));
         ui.add(Slider::new(&mut boidargs.alignment, 0.0..=2.0).text("Alignment"));
         ui.add(Slider::new(&mut boidargs.seperation, 0.0..=2.0).text("Separation"));
+        ui.add(Slider::new(&mut boidargs.avoidance, 0.0..=2.0).text("Avoidance"));
         ui.add(Slider::new(&mut boidargs.range, 0.0..=400.0).text("View range"));
+
+        ui.separator();
+        ui.label(format!("Boids: {current_count}"));
+        ui.label(format!("FPS: {fps:.1}"));
+        ui.label(format!("Frame time: {frame_time:.2} ms"));
+        ui.add(Slider::new(&mut boid_count.0, 1..=1000).text("Spawn count"));
+        ui.horizontal(|ui| {
+            if ui.button(format!("Spawn {}", boid_count.0)).clicked() {
+                spawn_random_boids(
+                    &mut commands,
+                    &assets,
+                    &bounds,
+                    window.single(),
+                    boid_count.0,
+                );
+            }
+            if ui.button("Despawn all").clicked() {
+                for entity in &birds {
+                    commands.entity(entity).despawn();
+                }
+            }
+        });
+
+        ui.separator();
+        ui.checkbox(&mut benchmark.enabled, "Benchmark mode");
+        ui.add(Slider::new(&mut benchmark.target, 1_000..=50_000).text("Target boid count"));
+
+        ui.separator();
+        ui.label("Right-click to place an attractor (predator if negative)");
+        ui.add(
+            Slider::new(&mut attractor_args.strength, -50_000.0..=50_000.0)
+                .text("Attractor strength"),
+        );
     });
 }